@@ -5,6 +5,7 @@ use bytes::{BufMut, Bytes};
 use reth_rlp::{Decodable, DecodeError, Encodable, RlpDecodable, RlpEncodable};
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
+use std::collections::HashMap;
 
 /// A Capability message consisting of the message-id and the payload
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
@@ -42,16 +43,20 @@ impl Capability {
         Self { name, version }
     }
 
-    /// Whether this is eth v66 protocol.
-    #[inline]
-    pub fn is_eth_v66(&self) -> bool {
-        self.name == "eth" && self.version == 66
+    /// Creates a new `Capability`, validating that `name` is a well-formed devp2p subprotocol
+    /// name: non-empty and no longer than [`MAX_CAPABILITY_NAME_LEN`] bytes.
+    pub fn try_new(name: SmolStr, version: usize) -> Result<Self, CapabilityNameError> {
+        validate_capability_name(&name)?;
+        Ok(Self { name, version })
     }
 
-    /// Whether this is eth v67.
+    /// Returns the corresponding [`EthVersion`] if this is a valid, supported `eth` capability.
     #[inline]
-    pub fn is_eth_v67(&self) -> bool {
-        self.name == "eth" && self.version == 67
+    pub fn eth_version(&self) -> Option<EthVersion> {
+        if self.name != "eth" {
+            return None
+        }
+        u8::try_from(self.version).ok().and_then(|version| EthVersion::try_from(version).ok())
     }
 }
 
@@ -60,8 +65,8 @@ impl Capability {
 pub struct Capabilities {
     /// All Capabilities and their versions
     inner: Vec<Capability>,
-    eth_66: bool,
-    eth_67: bool,
+    /// The highest `eth` version supported, if any.
+    eth_version: Option<EthVersion>,
 }
 
 impl Capabilities {
@@ -80,29 +85,120 @@ impl Capabilities {
     /// Whether the peer supports `eth` sub-protocol.
     #[inline]
     pub fn supports_eth(&self) -> bool {
-        self.eth_67 || self.eth_66
+        self.eth_version.is_some()
     }
 
-    /// Whether this peer supports eth v66 protocol.
+    /// Returns the highest supported `eth` version advertised in these capabilities, if any.
     #[inline]
-    pub fn supports_eth_v66(&self) -> bool {
-        self.eth_66
+    pub fn eth_version(&self) -> Option<EthVersion> {
+        self.eth_version
     }
 
-    /// Whether this peer supports eth v67 protocol.
+    /// Whether this peer supports `eth` at the given version or higher.
     #[inline]
-    pub fn supports_eth_v67(&self) -> bool {
-        self.eth_67
+    pub fn supports_eth_at_least(&self, version: EthVersion) -> bool {
+        match self.eth_version {
+            Some(supported) => supported as u8 >= version as u8,
+            None => false,
+        }
     }
+
+    /// Determines the [`SharedCapability`]s by intersecting this (the remote peer's) capability
+    /// list with the given local capabilities, following the devp2p negotiation rule:
+    ///
+    ///  1. A capability name is shared only if `local` and `self` both advertise the exact same
+    /// `(name, version)` pair; if a name has multiple such matching versions, the highest wins.
+    ///  2. Names present on only one side are dropped.
+    ///  3. The surviving capabilities are sorted alphabetically by name.
+    ///  4. Message-id offsets are assigned in that order, starting at
+    /// [`MIN_SHARED_CAPABILITY_OFFSET`]; each capability's offset is the previous offset plus the
+    /// previous capability's [`SharedCapability::num_messages`].
+    ///
+    /// Non-`eth` capabilities must have been registered in `registry` for their message count to
+    /// be resolvable, see [`CapabilityRegistry`].
+    ///
+    /// Returns [`SharedCapabilityError::NoSharedCapabilities`] if the intersection is empty; the
+    /// caller should treat this peer as useless and disconnect.
+    pub fn shared_capabilities(
+        &self,
+        local: &[Capability],
+        registry: &CapabilityRegistry,
+    ) -> Result<Vec<SharedCapability>, SharedCapabilityError> {
+        let mut shared = HashMap::<SmolStr, Capability>::new();
+        for remote_cap in &self.inner {
+            for local_cap in local {
+                if local_cap.name != remote_cap.name || local_cap.version != remote_cap.version {
+                    continue
+                }
+
+                match shared.get(&remote_cap.name) {
+                    Some(existing) if existing.version >= remote_cap.version => {}
+                    _ => {
+                        shared.insert(remote_cap.name.clone(), remote_cap.clone());
+                    }
+                }
+            }
+        }
+
+        if shared.is_empty() {
+            return Err(SharedCapabilityError::NoSharedCapabilities)
+        }
+
+        let mut shared = shared.into_values().collect::<Vec<_>>();
+        shared.sort_unstable_by(|a, b| a.name.cmp(&b.name));
+
+        let mut offset = MIN_SHARED_CAPABILITY_OFFSET;
+        shared
+            .into_iter()
+            .map(|cap| {
+                let shared_cap = SharedCapability::new(&cap.name, cap.version as u8, offset, registry)?;
+                offset += shared_cap.num_messages()?;
+                Ok(shared_cap)
+            })
+            .collect()
+    }
+}
+
+/// Returns the highest `eth` version advertised by the given capabilities, if any.
+fn highest_eth_version(capabilities: &[Capability]) -> Option<EthVersion> {
+    capabilities.iter().filter_map(Capability::eth_version).max_by_key(|version| *version as u8)
+}
+
+/// The maximum length, in bytes, of a devp2p subprotocol name.
+///
+/// OpenEthereum moved from a fixed 3-byte name to allowing up to 8 bytes, warning and ignoring
+/// anything longer; reth rejects oversized names outright instead.
+pub const MAX_CAPABILITY_NAME_LEN: usize = 8;
+
+/// An error indicating an invalid devp2p subprotocol name.
+#[derive(Debug, thiserror::Error)]
+pub enum CapabilityNameError {
+    /// The subprotocol name was empty.
+    #[error("subprotocol name must not be empty")]
+    Empty,
+    /// The subprotocol name exceeded [`MAX_CAPABILITY_NAME_LEN`] bytes.
+    #[error("subprotocol name `{name}` exceeds the maximum length of {MAX_CAPABILITY_NAME_LEN} bytes")]
+    TooLong {
+        /// The offending name.
+        name: SmolStr,
+    },
+}
+
+/// Validates that `name` is a non-empty devp2p subprotocol name of at most
+/// [`MAX_CAPABILITY_NAME_LEN`] bytes.
+fn validate_capability_name(name: &str) -> Result<(), CapabilityNameError> {
+    if name.is_empty() {
+        return Err(CapabilityNameError::Empty)
+    }
+    if name.len() > MAX_CAPABILITY_NAME_LEN {
+        return Err(CapabilityNameError::TooLong { name: name.into() })
+    }
+    Ok(())
 }
 
 impl From<Vec<Capability>> for Capabilities {
     fn from(value: Vec<Capability>) -> Self {
-        Self {
-            eth_66: value.iter().any(Capability::is_eth_v66),
-            eth_67: value.iter().any(Capability::is_eth_v67),
-            inner: value,
-        }
+        Self { eth_version: highest_eth_version(&value), inner: value }
     }
 }
 
@@ -116,14 +212,97 @@ impl Decodable for Capabilities {
     fn decode(buf: &mut &[u8]) -> Result<Self, DecodeError> {
         let inner = Vec::<Capability>::decode(buf)?;
 
-        Ok(Self {
-            eth_66: inner.iter().any(Capability::is_eth_v66),
-            eth_67: inner.iter().any(Capability::is_eth_v67),
-            inner,
-        })
+        for capability in &inner {
+            validate_capability_name(&capability.name)
+                .map_err(|_| DecodeError::Custom("invalid subprotocol name"))?;
+        }
+
+        Ok(Self { eth_version: highest_eth_version(&inner), inner })
     }
 }
 
+/// A subprotocol other than `eth` that can be negotiated alongside it over the same RLPx
+/// session, e.g. a `shh` or custom "extension" protocol.
+///
+/// Implementors describe their identity and how many message ids they need reserved; the
+/// [`CapabilityRegistry`] uses this to resolve [`SharedCapability::num_messages`] for
+/// capabilities that aren't `eth`.
+pub trait Subprotocol {
+    /// The name of the subprotocol, e.g. `"shh"`.
+    fn name(&self) -> SmolStr;
+
+    /// The version of the subprotocol.
+    fn version(&self) -> u8;
+
+    /// The number of protocol messages reserved for this subprotocol.
+    fn message_count(&self) -> u8;
+}
+
+/// A concrete, by-value [`Subprotocol`] describing a custom, non-`eth` capability.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CustomCapability {
+    /// The name of the subprotocol.
+    pub name: SmolStr,
+    /// The version of the subprotocol.
+    pub version: u8,
+    /// The number of protocol messages reserved for this subprotocol.
+    pub message_count: u8,
+}
+
+impl CustomCapability {
+    /// Creates a new [`CustomCapability`] with the given name, version, and message count.
+    pub fn new(name: impl Into<SmolStr>, version: u8, message_count: u8) -> Self {
+        Self { name: name.into(), version, message_count }
+    }
+}
+
+impl Subprotocol for CustomCapability {
+    fn name(&self) -> SmolStr {
+        self.name.clone()
+    }
+
+    fn version(&self) -> u8 {
+        self.version
+    }
+
+    fn message_count(&self) -> u8 {
+        self.message_count
+    }
+}
+
+/// A registry of locally known, non-`eth` subprotocols, keyed by name and version.
+///
+/// The network layer populates this with every [`Subprotocol`] it wants to run alongside `eth`
+/// so that capability negotiation can resolve a message count for them, the same way
+/// OpenEthereum let third-party subprotocols like whisper register themselves instead of being
+/// hard-coded.
+#[derive(Debug, Clone, Default)]
+pub struct CapabilityRegistry {
+    custom: HashMap<(SmolStr, u8), u8>,
+}
+
+impl CapabilityRegistry {
+    /// Creates an empty [`CapabilityRegistry`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a custom subprotocol, making it resolvable during capability negotiation.
+    pub fn register(&mut self, capability: &dyn Subprotocol) {
+        self.custom.insert((capability.name(), capability.version()), capability.message_count());
+    }
+
+    /// Returns the number of messages reserved for the given registered capability, if any.
+    pub fn message_count(&self, name: &str, version: u8) -> Option<u8> {
+        self.custom.get(&(SmolStr::new(name), version)).copied()
+    }
+}
+
+/// The first message-id offset assignable to a negotiated [`SharedCapability`].
+///
+/// Message ids below this are reserved for the base devp2p wire protocol (e.g. `Hello`, `Disconnect`, `Ping`, `Pong`).
+pub const MIN_SHARED_CAPABILITY_OFFSET: u8 = 0x10;
+
 /// This represents a shared capability, its version, and its offset.
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 #[allow(missing_docs)]
@@ -131,16 +310,29 @@ pub enum SharedCapability {
     /// The `eth` capability.
     Eth { version: EthVersion, offset: u8 },
 
-    /// An unknown capability.
-    UnknownCapability { name: SmolStr, version: u8, offset: u8 },
+    /// A registered, non-`eth` capability.
+    UnknownCapability { name: SmolStr, version: u8, messages: u8, offset: u8 },
 }
 
 impl SharedCapability {
     /// Creates a new [`SharedCapability`] based on the given name, offset, and version.
-    pub(crate) fn new(name: &str, version: u8, offset: u8) -> Result<Self, SharedCapabilityError> {
+    ///
+    /// Capabilities other than `eth` must have been registered in `registry` beforehand,
+    /// otherwise this returns [`SharedCapabilityError::UnknownCapability`].
+    pub(crate) fn new(
+        name: &str,
+        version: u8,
+        offset: u8,
+        registry: &CapabilityRegistry,
+    ) -> Result<Self, SharedCapabilityError> {
         match name {
             "eth" => Ok(Self::Eth { version: EthVersion::try_from(version)?, offset }),
-            _ => Ok(Self::UnknownCapability { name: name.into(), version, offset }),
+            _ => {
+                let messages = registry
+                    .message_count(name, version)
+                    .ok_or(SharedCapabilityError::UnknownCapability)?;
+                Ok(Self::UnknownCapability { name: name.into(), version, messages, offset })
+            }
         }
     }
 
@@ -172,7 +364,7 @@ impl SharedCapability {
     pub fn num_messages(&self) -> Result<u8, SharedCapabilityError> {
         match self {
             SharedCapability::Eth { version, .. } => Ok(version.total_messages()),
-            _ => Err(SharedCapabilityError::UnknownCapability),
+            SharedCapability::UnknownCapability { messages, .. } => Ok(*messages),
         }
     }
 }
@@ -186,6 +378,94 @@ pub enum SharedCapabilityError {
     /// Cannot determine the number of messages for unknown capabilities.
     #[error("cannot determine the number of messages for unknown capabilities")]
     UnknownCapability,
+    /// No capability is shared between the local and remote capability sets.
+    #[error("no shared capabilities between local and remote peer")]
+    NoSharedCapabilities,
+}
+
+/// The negotiated [`SharedCapability`]s of a session, used to demultiplex incoming wire messages
+/// to the subprotocol that owns them and to multiplex outgoing ones back onto the wire.
+///
+/// Capabilities are addressed by message-id offset: a message with wire id `id` belongs to the
+/// capability with the greatest offset `<= id` such that `id - offset` is within that
+/// capability's [`SharedCapability::num_messages`]. This is constructed from the output of
+/// [`Capabilities::shared_capabilities`], which is already sorted by ascending offset.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SharedCapabilities {
+    /// Negotiated capabilities, sorted by ascending offset.
+    capabilities: Vec<SharedCapability>,
+}
+
+impl SharedCapabilities {
+    /// Creates a new [`SharedCapabilities`] from negotiated capabilities.
+    ///
+    /// The given capabilities must be sorted by ascending offset, as returned by
+    /// [`Capabilities::shared_capabilities`].
+    pub fn new(capabilities: Vec<SharedCapability>) -> Self {
+        Self { capabilities }
+    }
+
+    /// Returns an iterator over the negotiated capabilities.
+    pub fn iter(&self) -> impl Iterator<Item = &SharedCapability> {
+        self.capabilities.iter()
+    }
+
+    /// Returns the negotiated capability with the given name, if any.
+    pub fn find_by_name(&self, name: &str) -> Option<&SharedCapability> {
+        self.capabilities.iter().find(|capability| capability.name() == name)
+    }
+
+    /// Finds the capability that owns the given wire message id.
+    ///
+    /// Returns the owning capability together with the message id normalized to be local to that
+    /// capability's protocol, or `None` if no negotiated capability claims `id`.
+    pub fn find_by_message_id(&self, id: u8) -> Option<(&SharedCapability, u8)> {
+        let capability = self
+            .capabilities
+            .iter()
+            .filter(|capability| capability.offset() <= id)
+            .max_by_key(|capability| capability.offset())?;
+
+        let local_id = id - capability.offset();
+        if local_id >= capability.num_messages().ok()? {
+            return None
+        }
+
+        Some((capability, local_id))
+    }
+
+    /// Routes an incoming wire-level message to the capability that owns it, normalizing its
+    /// message id to be local to that capability's protocol.
+    ///
+    /// Returns `None` if no negotiated capability claims the message's wire id.
+    pub fn route(
+        &self,
+        message: RawCapabilityMessage,
+    ) -> Option<(&SharedCapability, RawCapabilityMessage)> {
+        let id = u8::try_from(message.id).ok()?;
+        let (capability, local_id) = self.find_by_message_id(id)?;
+        Some((capability, RawCapabilityMessage { id: local_id as usize, payload: message.payload }))
+    }
+
+    /// Encodes a protocol-local message id back into its wire-level message id by adding the
+    /// capability's offset, the reverse of [`SharedCapabilities::route`].
+    ///
+    /// Returns `None` if the local id is out of range for the capability.
+    pub fn unroute(
+        &self,
+        capability: &SharedCapability,
+        message: RawCapabilityMessage,
+    ) -> Option<RawCapabilityMessage> {
+        let local_id = u8::try_from(message.id).ok()?;
+        if local_id >= capability.num_messages().ok()? {
+            return None
+        }
+
+        Some(RawCapabilityMessage {
+            id: (capability.offset() + local_id) as usize,
+            payload: message.payload,
+        })
+    }
 }
 
 #[cfg(test)]
@@ -194,7 +474,8 @@ mod tests {
 
     #[test]
     fn from_eth_67() {
-        let capability = SharedCapability::new("eth", 67, 0).unwrap();
+        let registry = CapabilityRegistry::new();
+        let capability = SharedCapability::new("eth", 67, 0, &registry).unwrap();
 
         assert_eq!(capability.name(), "eth");
         assert_eq!(capability.version(), 67);
@@ -203,10 +484,165 @@ mod tests {
 
     #[test]
     fn from_eth_66() {
-        let capability = SharedCapability::new("eth", 66, 0).unwrap();
+        let registry = CapabilityRegistry::new();
+        let capability = SharedCapability::new("eth", 66, 0, &registry).unwrap();
 
         assert_eq!(capability.name(), "eth");
         assert_eq!(capability.version(), 66);
         assert_eq!(capability, SharedCapability::Eth { version: EthVersion::Eth66, offset: 0 });
     }
+
+    #[test]
+    fn custom_capability_resolves_via_registry() {
+        let mut registry = CapabilityRegistry::new();
+        registry.register(&CustomCapability::new("shh", 3, 5));
+
+        let capability = SharedCapability::new("shh", 3, 16, &registry).unwrap();
+
+        assert_eq!(capability.name(), "shh");
+        assert_eq!(capability.version(), 3);
+        assert_eq!(capability.num_messages().unwrap(), 5);
+    }
+
+    #[test]
+    fn unregistered_custom_capability_is_rejected() {
+        let registry = CapabilityRegistry::new();
+        let err = SharedCapability::new("shh", 3, 16, &registry).unwrap_err();
+
+        assert!(matches!(err, SharedCapabilityError::UnknownCapability));
+    }
+
+    #[test]
+    fn shared_capabilities_picks_highest_matching_version() {
+        let remote = Capabilities::from(vec![
+            Capability::new("eth".into(), 66),
+            Capability::new("eth".into(), 67),
+        ]);
+        let local = [Capability::new("eth".into(), 66), Capability::new("eth".into(), 67)];
+
+        let shared = remote.shared_capabilities(&local, &CapabilityRegistry::new()).unwrap();
+
+        assert_eq!(
+            shared,
+            vec![SharedCapability::Eth {
+                version: EthVersion::Eth67,
+                offset: MIN_SHARED_CAPABILITY_OFFSET
+            }]
+        );
+    }
+
+    #[test]
+    fn shared_capabilities_assigns_sequential_offsets() {
+        let mut registry = CapabilityRegistry::new();
+        registry.register(&CustomCapability::new("shh", 3, 5));
+
+        let remote = Capabilities::from(vec![
+            Capability::new("eth".into(), 67),
+            Capability::new("shh".into(), 3),
+        ]);
+        let local = [Capability::new("eth".into(), 67), Capability::new("shh".into(), 3)];
+
+        let shared = remote.shared_capabilities(&local, &registry).unwrap();
+
+        assert_eq!(
+            shared,
+            vec![
+                SharedCapability::Eth { version: EthVersion::Eth67, offset: 0x10 },
+                SharedCapability::UnknownCapability {
+                    name: "shh".into(),
+                    version: 3,
+                    messages: 5,
+                    offset: 0x10 + EthVersion::Eth67.total_messages()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn shared_capabilities_errors_when_empty() {
+        let remote = Capabilities::from(vec![Capability::new("eth".into(), 66)]);
+        let local = [Capability::new("les".into(), 4)];
+
+        let err = remote.shared_capabilities(&local, &CapabilityRegistry::new()).unwrap_err();
+
+        assert!(matches!(err, SharedCapabilityError::NoSharedCapabilities));
+    }
+
+    fn negotiate_shh_and_eth() -> SharedCapabilities {
+        let mut registry = CapabilityRegistry::new();
+        registry.register(&CustomCapability::new("shh", 3, 5));
+
+        let remote = Capabilities::from(vec![
+            Capability::new("eth".into(), 67),
+            Capability::new("shh".into(), 3),
+        ]);
+        let local = [Capability::new("eth".into(), 67), Capability::new("shh".into(), 3)];
+
+        SharedCapabilities::new(remote.shared_capabilities(&local, &registry).unwrap())
+    }
+
+    #[test]
+    fn routes_message_to_owning_capability() {
+        let shared = negotiate_shh_and_eth();
+        let eth_messages = EthVersion::Eth67.total_messages();
+
+        let message = RawCapabilityMessage { id: 0x10, payload: Bytes::from_static(b"eth") };
+        let (capability, normalized) = shared.route(message).unwrap();
+        assert_eq!(capability.name(), "eth");
+        assert_eq!(normalized.id, 0);
+
+        let shh_wire_id = 0x10 + eth_messages as usize + 1;
+        let message = RawCapabilityMessage { id: shh_wire_id, payload: Bytes::from_static(b"shh") };
+        let (capability, normalized) = shared.route(message).unwrap();
+        assert_eq!(capability.name(), "shh");
+        assert_eq!(normalized.id, 1);
+    }
+
+    #[test]
+    fn rejects_message_id_outside_any_capability_range() {
+        let shared = negotiate_shh_and_eth();
+
+        let message = RawCapabilityMessage { id: 0xff, payload: Bytes::new() };
+        assert!(shared.route(message).is_none());
+    }
+
+    #[test]
+    fn unroute_is_the_inverse_of_route() {
+        let shared = negotiate_shh_and_eth();
+        let shh = shared.find_by_name("shh").unwrap();
+
+        let local = RawCapabilityMessage { id: 1, payload: Bytes::from_static(b"shh") };
+        let wire = shared.unroute(shh, local.clone()).unwrap();
+
+        let (capability, roundtripped) = shared.route(wire).unwrap();
+        assert_eq!(capability.name(), "shh");
+        assert_eq!(roundtripped.id, local.id);
+    }
+
+    #[test]
+    fn try_new_accepts_names_up_to_the_limit() {
+        let capability = Capability::try_new("eightbyt".into(), 1);
+        assert!(capability.is_ok());
+    }
+
+    #[test]
+    fn try_new_rejects_empty_name() {
+        let err = Capability::try_new("".into(), 1).unwrap_err();
+        assert!(matches!(err, CapabilityNameError::Empty));
+    }
+
+    #[test]
+    fn try_new_rejects_oversized_name() {
+        let err = Capability::try_new("waytoolongname".into(), 1).unwrap_err();
+        assert!(matches!(err, CapabilityNameError::TooLong { .. }));
+    }
+
+    #[test]
+    fn decoding_rejects_oversized_capability_name() {
+        let mut encoded = Vec::new();
+        vec![Capability::new("waytoolongname".into(), 1)].encode(&mut encoded);
+
+        let err = Capabilities::decode(&mut &encoded[..]).unwrap_err();
+        assert!(matches!(err, DecodeError::Custom(_)));
+    }
 }